@@ -1,29 +1,55 @@
 //! This module contains the functionality for the following comment-related features
-//! using the comment character defined in the user's `languages.toml`:
-//! * toggle comments on lines over the selection
+//! using the comment tokens defined in the user's `languages.toml`:
+//! * toggle line comments on lines over the selection
+//! * toggle block comments wrapping the selection
 //! * continue comment when opening a new line
 
 use crate::{chars, Change, Rope, RopeSlice, Selection, Tendril, Transaction};
 use std::borrow::Cow;
 
+/// The pair of tokens used to delimit a block comment, e.g. `/*` and `*/`, as
+/// configured by the `block-comment-tokens` key in `languages.toml`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockCommentToken {
+    pub start: String,
+    pub end: String,
+}
+
+impl Default for BlockCommentToken {
+    fn default() -> Self {
+        BlockCommentToken {
+            start: "/*".to_string(),
+            end: "*/".to_string(),
+        }
+    }
+}
+
+/// A commentable line: its index, the column of its own comment token (its
+/// first non-whitespace character) and the local margin (`0` or `1`) following
+/// that token. Each line is handled independently so that selections with
+/// uneven indentation round-trip correctly.
+struct LineComment {
+    line: usize,
+    col: usize,
+    margin: usize,
+}
+
 /// Given text, a comment token, and a set of line indices, returns the following:
 /// - Whether the given lines should be considered commented
 ///     - If any of the lines are uncommented, all lines are considered as such.
-/// - The lines to change for toggling comments
+/// - The lines to change for toggling comments, each with its own token column
+///   and margin.
 ///     - This is all provided lines excluding blanks lines.
-/// - The column of the comment tokens
-///     - Column of existing tokens, if the lines are commented; column to place tokens at otherwise.
-/// - The margin to the right of the comment tokens
-///     - Defaults to `1`. If any existing comment token is not followed by a space, changes to `0`.
+/// - The minimum first-non-whitespace column across those lines, used to align
+///   the tokens when commenting.
 fn find_line_comment(
     token: &str,
     text: RopeSlice,
     lines: impl IntoIterator<Item = usize>,
-) -> (bool, Vec<usize>, usize, usize) {
+) -> (bool, Vec<LineComment>, usize) {
     let mut commented = true;
     let mut to_change = Vec::new();
     let mut min = usize::MAX; // minimum col for find_first_non_whitespace_char
-    let mut margin = 1;
     let token_len = token.chars().count();
     for line in lines {
         let line_slice = text.line(line);
@@ -43,17 +69,19 @@ fn find_line_comment(
                 commented = false;
             }
 
-            // determine margin of 0 or 1 for uncommenting; if any comment token is not followed by a space,
-            // a margin of 0 is used for all lines.
-            if !matches!(line_slice.get_char(pos + token_len), Some(c) if c == ' ') {
-                margin = 0;
-            }
+            // determine this line's own margin (0 or 1) for uncommenting, independently of
+            // the other lines, so that mixed indentation doesn't mangle the selection.
+            let margin = usize::from(matches!(line_slice.get_char(pos + token_len), Some(' ')));
 
             // blank lines don't get pushed.
-            to_change.push(line);
+            to_change.push(LineComment {
+                line,
+                col: pos,
+                margin,
+            });
         }
     }
-    (commented, to_change, min, margin)
+    (commented, to_change, min)
 }
 
 #[must_use]
@@ -75,18 +103,18 @@ pub fn toggle_line_comments(doc: &Rope, selection: &Selection, token: Option<&st
         min_next_line = end;
     }
 
-    let (commented, to_change, min, margin) = find_line_comment(token, text, lines);
+    let (commented, to_change, min) = find_line_comment(token, text, lines);
 
     let mut changes: Vec<Change> = Vec::with_capacity(to_change.len());
 
-    for line in to_change {
-        let pos = text.line_to_char(line) + min;
-
+    for LineComment { line, col, margin } in to_change {
         if !commented {
-            // comment line
+            // comment line: align all tokens at the shared minimum column.
+            let pos = text.line_to_char(line) + min;
             changes.push((pos, pos, Some(comment.clone())));
         } else {
-            // uncomment line
+            // uncomment line: remove this line's own token at its own column.
+            let pos = text.line_to_char(line) + col;
             changes.push((pos, pos + token.len() + margin, None));
         }
     }
@@ -94,6 +122,180 @@ pub fn toggle_line_comments(doc: &Rope, selection: &Selection, token: Option<&st
     Transaction::change(doc, changes.into_iter())
 }
 
+/// The block-comment geometry of a single selection range: the char range
+/// spanning its first to last non-whitespace character, along with whether the
+/// surrounding comment tokens (when present) are followed/preceded by a margin
+/// space.
+struct BlockComment {
+    from: usize,
+    to: usize,
+    start_margin: bool,
+    end_margin: bool,
+}
+
+/// Given block comment tokens and a selection, returns the following:
+/// - Whether every (non-blank) range in the selection is already wrapped in the
+///   tokens.
+///     - If any range is uncommented, all ranges are considered as such.
+/// - The per-range geometry used to insert or remove the tokens.
+///     - Blank (whitespace-only) ranges are excluded.
+fn find_block_comments(
+    tokens: &BlockCommentToken,
+    text: RopeSlice,
+    selection: &Selection,
+) -> (bool, Vec<BlockComment>) {
+    let start_len = tokens.start.chars().count();
+    let end_len = tokens.end.chars().count();
+
+    let mut commented = true;
+    let mut only_whitespace = true;
+    let mut to_change = Vec::with_capacity(selection.len());
+
+    for range in selection {
+        let (start, end) = (range.from(), range.to());
+
+        // scan inward from each end to the range's first and last non-whitespace
+        // characters, tolerating a surrounding margin space like the line path does.
+        let from = (start..end).find(|&i| !text.char(i).is_whitespace());
+        let to = (start..end).rev().find(|&i| !text.char(i).is_whitespace());
+
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to + 1),
+            // blank ranges don't get wrapped.
+            _ => continue,
+        };
+        only_whitespace = false;
+
+        let is_commented = to - from >= start_len + end_len
+            && Cow::from(text.slice(from..from + start_len)) == tokens.start
+            && Cow::from(text.slice(to - end_len..to)) == tokens.end;
+
+        if !is_commented {
+            // as soon as one of the non-blank ranges isn't wrapped, the whole
+            // selection is considered uncommented.
+            commented = false;
+        }
+
+        // determine the margin following the start token and preceding the end token;
+        // a single space is stripped along with the token when uncommenting.
+        let start_margin = is_commented && matches!(text.get_char(from + start_len), Some(' '));
+        let end_margin = is_commented
+            && from + start_len < to - end_len
+            && matches!(text.get_char(to - end_len - 1), Some(' '));
+
+        to_change.push(BlockComment {
+            from,
+            to,
+            start_margin,
+            end_margin,
+        });
+    }
+
+    if only_whitespace {
+        commented = false;
+    }
+
+    (commented, to_change)
+}
+
+#[must_use]
+pub fn toggle_block_comments(
+    doc: &Rope,
+    selection: &Selection,
+    tokens: &BlockCommentToken,
+) -> Transaction {
+    let text = doc.slice(..);
+
+    let (commented, to_change) = find_block_comments(tokens, text, selection);
+
+    let start = Tendril::from(format!("{} ", tokens.start));
+    let end = Tendril::from(format!(" {}", tokens.end));
+    let start_len = tokens.start.chars().count();
+    let end_len = tokens.end.chars().count();
+
+    let mut changes: Vec<Change> = Vec::with_capacity(to_change.len() * 2);
+
+    for block in to_change {
+        let BlockComment {
+            from,
+            to,
+            start_margin,
+            end_margin,
+        } = block;
+
+        if !commented {
+            // wrap the range with the block comment tokens.
+            changes.push((from, from, Some(start.clone())));
+            changes.push((to, to, Some(end.clone())));
+        } else {
+            // unwrap the range, dropping a single margin space on each side.
+            let content_start = from + start_len + start_margin as usize;
+            let content_end = to - end_len - end_margin as usize;
+
+            if content_start >= content_end {
+                // nothing but the tokens (and margins) between them.
+                changes.push((from, to, None));
+            } else {
+                changes.push((from, content_start, None));
+                changes.push((content_end, to, None));
+            }
+        }
+    }
+
+    Transaction::change(doc, changes.into_iter())
+}
+
+/// Whether every range in the selection covers whole lines, i.e. starts at the
+/// beginning of a line and ends at a line boundary. Intra-line (partial)
+/// selections return `false`.
+fn selection_is_whole_lines(text: RopeSlice, selection: &Selection) -> bool {
+    selection.ranges().iter().all(|range| {
+        let (from, to) = (range.from(), range.to());
+        let from_at_line_start = from == text.line_to_char(text.char_to_line(from));
+        let to_at_line_boundary =
+            to == text.len_chars() || to == text.line_to_char(text.char_to_line(to));
+        from_at_line_start && to_at_line_boundary
+    })
+}
+
+/// Toggle comments over the selection, choosing between line and block comments
+/// based on the selection and the available tokens.
+///
+/// Line comments are preferred when a line token exists and the selection spans
+/// whole lines; intra-line (partial) selections and languages that only define
+/// block tokens fall back to block comments. A selection that is already block
+/// commented is uncommented as a block even when a line token is also available.
+#[must_use]
+pub fn toggle_comments(
+    doc: &Rope,
+    selection: &Selection,
+    line_token: Option<&str>,
+    block_tokens: Option<&BlockCommentToken>,
+) -> Transaction {
+    let text = doc.slice(..);
+
+    // already block commented: toggle the block off regardless of the line token.
+    if let Some(tokens) = block_tokens {
+        let (commented, _) = find_block_comments(tokens, text, selection);
+        if commented {
+            return toggle_block_comments(doc, selection, tokens);
+        }
+    }
+
+    match (line_token, block_tokens) {
+        (Some(token), Some(tokens)) => {
+            if selection_is_whole_lines(text, selection) {
+                toggle_line_comments(doc, selection, Some(token))
+            } else {
+                toggle_block_comments(doc, selection, tokens)
+            }
+        }
+        (Some(token), None) => toggle_line_comments(doc, selection, Some(token)),
+        (None, Some(tokens)) => toggle_block_comments(doc, selection, tokens),
+        (None, None) => toggle_line_comments(doc, selection, None),
+    }
+}
+
 /// Return the comment token of the current line if it is commented, along with the
 /// position of the last character in the comment token.
 /// Return None otherwise.
@@ -156,6 +358,56 @@ pub fn handle_comment_continue<'a>(
     }
 }
 
+/// Determines whether the new line following the line at `line_idx` continues a
+/// block comment and, if so, appends the aligned continuation prefix to `text`.
+///
+/// Recognises an opening line (`/*` or `/**`) as well as a continuation line
+/// (` *`), aligning the inserted `*` under the second character of the opener so
+/// that doc-comment styles such as Rustdoc `/** */` and JSDoc line up. Once the
+/// closing `*/` has been reached the block is over and `text` is left unchanged.
+///
+/// Returns `true` when a continuation was inserted.
+pub fn handle_block_comment_continue<'a>(
+    doc: &'a Rope,
+    text: &'a mut String,
+    line_idx: usize,
+    block_tokens: &'a [BlockCommentToken],
+) -> bool {
+    let line = doc.line(line_idx);
+
+    let Some(pos) = chars::find_first_non_whitespace_char(line) else {
+        return false;
+    };
+
+    let fragment = Cow::from(line.slice(pos..line.len_chars()));
+    let fragment = fragment.trim_end();
+
+    for BlockCommentToken { start, end } in block_tokens {
+        // the line closes the block (`*/`, `/* ... */`); stop continuing.
+        if fragment.ends_with(end.as_str()) {
+            return false;
+        }
+
+        // opening line such as `/*` or `/**`: align the `*` under the opener's
+        // second character.
+        if fragment.starts_with(start.as_str()) {
+            text.push_str(" * ");
+            return true;
+        }
+
+        // continuation line such as ` * foo`: preserve the existing alignment.
+        if let Some(continuation) = end.chars().next() {
+            if fragment.starts_with(continuation) {
+                text.push(continuation);
+                text.push(' ');
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -169,9 +421,14 @@ mod test {
 
         let text = doc.slice(..);
 
-        let res = find_line_comment("//", text, 0..3);
-        // (commented = true, to_change = [line 0, line 2], min = col 2, margin = 0)
-        assert_eq!(res, (false, vec![0, 2], 2, 0));
+        let (commented, to_change, min) = find_line_comment("//", text, 0..3);
+        // (commented = false, to_change = [line 0, line 2], min = col 2)
+        assert!(!commented);
+        assert_eq!(
+            to_change.iter().map(|lc| lc.line).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(min, 2);
 
         // comment
         let transaction = toggle_line_comments(&doc, &selection, None);
@@ -209,7 +466,80 @@ mod test {
         assert_eq!(doc, "");
         assert!(selection.len() == 1); // to ignore the selection unused warning
 
-        // TODO: account for uncommenting with uneven comment indentation
+        // uneven comment indentation: uncommenting removes each line's token at its own
+        // column with its own margin.
+        doc = Rope::from("  // a\n    //b\n// c");
+        selection = Selection::single(0, doc.len_chars() - 1);
+
+        let transaction = toggle_line_comments(&doc, &selection, None);
+        transaction.apply(&mut doc);
+        selection = selection.map(transaction.changes());
+        assert_eq!(doc, "  a\n    b\nc");
+        assert!(selection.len() == 1); // to ignore the selection unused warning
+    }
+
+    #[test]
+    fn test_toggle_block_comments() {
+        let tokens = BlockCommentToken {
+            start: String::from("/*"),
+            end: String::from("*/"),
+        };
+
+        // wrap a multi-line selection.
+        let mut doc = Rope::from("foo\nbar");
+        let selection = Selection::single(0, doc.len_chars());
+
+        let transaction = toggle_block_comments(&doc, &selection, &tokens);
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "/* foo\nbar */");
+
+        // round-trip back to the original, ignoring leading/trailing whitespace.
+        let selection = Selection::single(0, doc.len_chars());
+        let transaction = toggle_block_comments(&doc, &selection, &tokens);
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "foo\nbar");
+
+        // tokens sitting flush against the content (no margin) still uncomment.
+        let mut doc = Rope::from("  /*foo*/");
+        let selection = Selection::single(0, doc.len_chars());
+        let transaction = toggle_block_comments(&doc, &selection, &tokens);
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "  foo");
+    }
+
+    #[test]
+    fn test_toggle_comments() {
+        let block = BlockCommentToken {
+            start: String::from("/*"),
+            end: String::from("*/"),
+        };
+
+        // whole-line selection with a line token available -> line comments.
+        let mut doc = Rope::from("foo\nbar\n");
+        let selection = Selection::single(0, doc.len_chars());
+        let transaction = toggle_comments(&doc, &selection, Some("//"), Some(&block));
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "// foo\n// bar\n");
+
+        // partial (intra-line) selection -> block comments even with a line token.
+        let mut doc = Rope::from("foo bar");
+        let selection = Selection::single(4, 7);
+        let transaction = toggle_comments(&doc, &selection, Some("//"), Some(&block));
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "foo /* bar */");
+
+        // an existing block comment uncomments as a block even with a line token.
+        let selection = Selection::single(4, doc.len_chars());
+        let transaction = toggle_comments(&doc, &selection, Some("//"), Some(&block));
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "foo bar");
+
+        // only block tokens configured -> block comments.
+        let mut doc = Rope::from("foo");
+        let selection = Selection::single(0, doc.len_chars());
+        let transaction = toggle_comments(&doc, &selection, None, Some(&block));
+        transaction.apply(&mut doc);
+        assert_eq!(doc, "/* foo */");
     }
 
     #[test]
@@ -283,4 +613,40 @@ mod test {
 
         handle_comment_continue(&doc, &mut text, 0, &comment_tokens);
     }
+
+    #[test]
+    fn test_handle_block_comment_continue() {
+        let block_tokens = vec![BlockCommentToken {
+            start: String::from("/*"),
+            end: String::from("*/"),
+        }];
+
+        // opening line aligns the `*` under the opener's second character.
+        let doc = Rope::from("/**\n");
+        let mut text = String::from(&doc);
+        assert!(handle_block_comment_continue(&doc, &mut text, 0, &block_tokens));
+        assert_eq!(text, String::from("/**\n * "));
+
+        let doc = Rope::from("    /* foo\n");
+        let mut text = String::from(&doc);
+        assert!(handle_block_comment_continue(&doc, &mut text, 0, &block_tokens));
+        assert_eq!(text, String::from("    /* foo\n * "));
+
+        // continuation line keeps the alignment.
+        let doc = Rope::from(" * bar\n");
+        let mut text = String::from(&doc);
+        assert!(handle_block_comment_continue(&doc, &mut text, 0, &block_tokens));
+        assert_eq!(text, String::from(" * bar\n* "));
+
+        // a closing line ends the block.
+        let doc = Rope::from(" */\n");
+        let mut text = String::from(&doc);
+        assert!(!handle_block_comment_continue(&doc, &mut text, 0, &block_tokens));
+        assert_eq!(text, String::from(" */\n"));
+
+        // a single-line block doesn't continue either.
+        let doc = Rope::from("/* foo */\n");
+        let mut text = String::from(&doc);
+        assert!(!handle_block_comment_continue(&doc, &mut text, 0, &block_tokens));
+    }
 }